@@ -5,9 +5,8 @@
 // <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
-use super::{Error, Transport};
-use bytes::{BufMut, BytesMut};
-use grammers_crypto::RingBuffer;
+use super::{Decoder, Encoder, Error, Transport};
+use bytes::{Buf, BufMut, BytesMut};
 
 /// The lightest MTProto transport protocol available. This is an
 /// implementation of the [abridged transport].
@@ -37,76 +36,166 @@ use grammers_crypto::RingBuffer;
 /// [abridged transport]: https://core.telegram.org/mtproto/mtproto-transports#abridged
 pub struct Abridged {
     init: bool,
+    recv_init: bool,
+    state: State,
+}
+
+/// The state of the [`Abridged::feed`] state machine, tracking how far
+/// into the current frame the incremental decoder has gotten so that a
+/// later call never has to re-read bytes a previous call already saw.
+#[allow(clippy::enum_variant_names)]
+enum State {
+    /// Waiting for the single length byte (or the `0x7f` marker) that
+    /// starts a new frame.
+    NeedHeaderByte,
+    /// Saw the `0x7f` marker and is waiting for the rest of the 3-byte
+    /// little-endian length that follows it, which may itself be split
+    /// across chunk boundaries.
+    NeedLengthTail { got: [u8; 4], have: usize },
+    /// The length is known; waiting for `remaining` more payload bytes.
+    NeedPayload { remaining: usize },
 }
 
 #[allow(clippy::new_without_default)]
 impl Abridged {
     pub fn new() -> Self {
-        Self { init: false }
+        Self {
+            init: false,
+            recv_init: false,
+            state: State::NeedHeaderByte,
+        }
     }
 }
 
 impl Transport for Abridged {
-    fn pack(&mut self, input: &[u8], output: &mut RingBuffer<u8>) {
-        assert_eq!(input.len() % 4, 0);
+    fn pack<B: BufMut>(&mut self, input: &mut impl Buf, output: &mut B) {
+        assert_eq!(input.remaining() % 4, 0);
 
-        if !self.init {
-            output.push(0xef);
-            self.init = true;
-        }
+        {
+            let mut encoder = Encoder::new(output);
+            if !self.init {
+                encoder.write_u8(0xef);
+                self.init = true;
+            }
 
-        let len = input.len() / 4;
-        if len < 127 {
-            output.push(len as u8);
-            output.extend(input);
-        } else {
-            output.push(0x7f);
-            output.extend(&(len as u32).to_le_bytes()[..3]);
-            output.extend(input);
+            let len = input.remaining() / 4;
+            if len < 127 {
+                encoder.write_u8(len as u8);
+            } else {
+                encoder.write_u8(0x7f);
+                encoder.write_uint_le(len as u32, 3);
+            }
         }
+        output.put(input);
     }
 
-    fn unpack(&mut self, input: &[u8], output: &mut BytesMut) -> Result<usize, Error> {
-        if input.is_empty() {
+    fn unpack<B: Buf>(&mut self, input: &mut B, output: &mut impl BufMut) -> Result<usize, Error> {
+        if !input.has_remaining() {
             return Err(Error::MissingBytes);
         }
 
-        let header_len;
-        let len = input[0];
-        let len = if len < 127 {
-            header_len = 1;
-            len as i32
-        } else {
-            if input.len() < 4 {
-                return Err(Error::MissingBytes);
-            }
+        let marker = input.chunk()[0];
+        let header_len = if marker < 127 { 1 } else { 4 };
 
-            header_len = 4;
-            let mut len = [0; 4];
-            len[..3].copy_from_slice(&input[1..4]);
-            i32::from_le_bytes(len)
-        };
-
-        let len = len * 4;
-        if (input.len() as i32) < header_len + len {
+        // Peek the header — `chunk()` never advances `input` — and keep
+        // peeking until the *whole* frame (header + payload) is confirmed
+        // present. Nothing gets consumed until then, so a short read can
+        // always be retried later against the same, untouched `input`.
+        if input.chunk().len() < header_len {
             return Err(Error::MissingBytes);
         }
+        let mut decoder = Decoder::new(&input.chunk()[..header_len]);
+        let marker = decoder.read_u8()?;
+        let len = if marker < 127 {
+            marker as usize
+        } else {
+            decoder.read_uint_le(3)? as usize
+        } * 4;
 
-        let header_len = header_len as usize;
-        let len = len as usize;
+        if input.remaining() < header_len + len {
+            return Err(Error::MissingBytes);
+        }
 
-        output.put(&input[header_len..header_len + len]);
+        input.advance(header_len);
+        output.put(&mut input.take(len));
         Ok(header_len + len)
     }
 
+    fn feed(&mut self, chunk: &[u8], output: &mut BytesMut) -> Result<Option<usize>, Error> {
+        let mut offset = 0;
+
+        if !self.recv_init {
+            if chunk.is_empty() {
+                return Ok(None);
+            }
+            offset += 1;
+            self.recv_init = true;
+        }
+
+        loop {
+            match &mut self.state {
+                State::NeedHeaderByte => {
+                    if offset >= chunk.len() {
+                        return Ok(None);
+                    }
+                    let marker = chunk[offset];
+                    offset += 1;
+                    self.state = if marker < 127 {
+                        State::NeedPayload {
+                            remaining: marker as usize * 4,
+                        }
+                    } else {
+                        State::NeedLengthTail {
+                            got: [0; 4],
+                            have: 0,
+                        }
+                    };
+                }
+                State::NeedLengthTail { got, have } => {
+                    while *have < 3 {
+                        if offset >= chunk.len() {
+                            return Ok(None);
+                        }
+                        got[*have] = chunk[offset];
+                        offset += 1;
+                        *have += 1;
+                    }
+                    let remaining = u32::from_le_bytes(*got) as usize * 4;
+                    self.state = State::NeedPayload { remaining };
+                }
+                State::NeedPayload { remaining } => {
+                    if *remaining == 0 {
+                        self.state = State::NeedHeaderByte;
+                        return Ok(Some(offset));
+                    }
+                    if offset >= chunk.len() {
+                        return Ok(None);
+                    }
+                    let take = (chunk.len() - offset).min(*remaining);
+                    output.put(&chunk[offset..offset + take]);
+                    offset += take;
+                    *remaining -= take;
+                    if *remaining == 0 {
+                        self.state = State::NeedHeaderByte;
+                        return Ok(Some(offset));
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
     fn reset(&mut self) {
         self.init = false;
+        self.recv_init = false;
+        self.state = State::NeedHeaderByte;
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use grammers_crypto::RingBuffer;
 
     /// Returns a new abridged transport, `n` bytes of input data for it, and an empty output buffer.
     fn setup_pack(n: u32) -> (Abridged, Vec<u8>, RingBuffer<u8>) {
@@ -117,7 +206,7 @@ mod tests {
     #[test]
     fn pack_empty() {
         let (mut transport, input, mut output) = setup_pack(0);
-        transport.pack(&input, &mut output);
+        transport.pack_slice(&input, &mut output);
         assert_eq!(&output[..], &[0xef, 0]);
     }
 
@@ -125,13 +214,13 @@ mod tests {
     #[should_panic]
     fn pack_non_padded() {
         let (mut transport, input, mut output) = setup_pack(7);
-        transport.pack(&input, &mut output);
+        transport.pack_slice(&input, &mut output);
     }
 
     #[test]
     fn pack_normal() {
         let (mut transport, input, mut output) = setup_pack(128);
-        transport.pack(&input, &mut output);
+        transport.pack_slice(&input, &mut output);
         assert_eq!(&output[..2], &[0xef, 32]);
         assert_eq!(&output[2..output.len()], &input[..]);
     }
@@ -139,7 +228,7 @@ mod tests {
     #[test]
     fn pack_large() {
         let (mut transport, input, mut output) = setup_pack(1024);
-        transport.pack(&input, &mut output);
+        transport.pack_slice(&input, &mut output);
         assert_eq!(&output[..5], &[0xef, 127, 0, 1, 0]);
         assert_eq!(&output[5..], &input[..]);
     }
@@ -150,7 +239,7 @@ mod tests {
         let input = [1];
         let mut output = BytesMut::new();
         assert_eq!(
-            transport.unpack(&input, &mut output),
+            transport.unpack_slice(&input, &mut output),
             Err(Error::MissingBytes)
         );
     }
@@ -159,8 +248,8 @@ mod tests {
     fn unpack_normal() {
         let (mut transport, input, mut packed) = setup_pack(128);
         let mut unpacked = BytesMut::new();
-        transport.pack(&input, &mut packed);
-        transport.unpack(&packed[1..], &mut unpacked).unwrap();
+        transport.pack_slice(&input, &mut packed);
+        transport.unpack_slice(&packed[1..], &mut unpacked).unwrap();
         assert_eq!(input, unpacked);
     }
 
@@ -168,7 +257,7 @@ mod tests {
     fn unpack_two_at_once() {
         let (mut transport, input, mut packed) = setup_pack(128);
         let mut unpacked = BytesMut::new();
-        transport.pack(&input, &mut packed);
+        transport.pack_slice(&input, &mut packed);
         let two_input = packed
             .as_ref()
             .iter()
@@ -176,17 +265,162 @@ mod tests {
             .skip(1)
             .chain(packed.as_ref().iter().copied().skip(1))
             .collect::<Vec<_>>();
-        let n = transport.unpack(&two_input, &mut unpacked).unwrap();
+        let n = transport.unpack_slice(&two_input, &mut unpacked).unwrap();
         assert_eq!(input, unpacked);
         assert_eq!(n, packed.len() - 1);
     }
 
+    #[test]
+    fn unpack_truncated_payload_does_not_consume_header() {
+        let (mut transport, input, mut packed) = setup_pack(128);
+        let mut unpacked = BytesMut::new();
+        transport.pack_slice(&input, &mut packed);
+        // A complete header but a truncated payload: the marker/length byte
+        // must stay in the buffer, not be silently eaten, so a retry once
+        // the rest of the frame arrives can still find it.
+        let mut buf = BytesMut::new();
+        buf.put_slice(&packed[1..packed.len() - 1]);
+
+        let before = buf.remaining();
+        assert_eq!(
+            transport.unpack(&mut buf, &mut unpacked),
+            Err(Error::MissingBytes)
+        );
+        assert_eq!(buf.remaining(), before);
+
+        buf.put_slice(&packed[packed.len() - 1..]);
+        transport.unpack(&mut buf, &mut unpacked).unwrap();
+        assert_eq!(input, unpacked);
+    }
+
+    #[test]
+    fn unpack_reused_buffer_keeps_second_frame() {
+        let (mut transport, input, mut packed) = setup_pack(128);
+        let mut unpacked = BytesMut::new();
+        transport.pack_slice(&input, &mut packed);
+
+        // Two frames back-to-back in a single read, as a real socket would
+        // hand them over, fed through one `Buf` that outlives the call.
+        let mut two_frames = BytesMut::new();
+        two_frames.put_slice(&packed[1..]);
+        two_frames.put_slice(&packed[1..]);
+
+        let second_frame_len = packed.len() - 1;
+        let n = transport.unpack(&mut two_frames, &mut unpacked).unwrap();
+        assert_eq!(input, unpacked);
+        assert_eq!(n, second_frame_len);
+        // The second frame must still be sitting in the buffer, untouched.
+        assert_eq!(two_frames.remaining(), second_frame_len);
+
+        unpacked.clear();
+        transport.unpack(&mut two_frames, &mut unpacked).unwrap();
+        assert_eq!(input, unpacked);
+        assert_eq!(two_frames.remaining(), 0);
+    }
+
     #[test]
     fn unpack_large() {
         let (mut transport, input, mut packed) = setup_pack(1024);
         let mut unpacked = BytesMut::new();
-        transport.pack(&input, &mut packed);
-        transport.unpack(&packed[1..], &mut unpacked).unwrap();
+        transport.pack_slice(&input, &mut packed);
+        transport.unpack_slice(&packed[1..], &mut unpacked).unwrap();
+        assert_eq!(input, unpacked);
+    }
+
+    #[test]
+    fn feed_whole_frame_at_once() {
+        let (mut transport, input, mut packed) = setup_pack(128);
+        transport.pack_slice(&input, &mut packed);
+
+        let mut fed = Abridged::new();
+        let mut output = BytesMut::new();
+        let n = fed.feed(&packed, &mut output).unwrap();
+        assert_eq!(n, Some(packed.len()));
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn feed_one_byte_at_a_time() {
+        let (mut transport, input, mut packed) = setup_pack(128);
+        transport.pack_slice(&input, &mut packed);
+
+        let mut fed = Abridged::new();
+        let mut output = BytesMut::new();
+        let mut consumed = 0;
+        for byte in packed.as_ref() {
+            match fed.feed(&[*byte], &mut output).unwrap() {
+                Some(n) => consumed += n,
+                None => consumed += 1,
+            }
+        }
+        assert_eq!(consumed, packed.len());
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn feed_large_across_chunks() {
+        let (mut transport, input, mut packed) = setup_pack(1024);
+        transport.pack_slice(&input, &mut packed);
+
+        let mut fed = Abridged::new();
+        let mut output = BytesMut::new();
+        for chunk in packed.as_ref().chunks(7) {
+            fed.feed(chunk, &mut output).unwrap();
+        }
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn feed_two_frames_in_one_chunk() {
+        let (mut transport, input, mut packed) = setup_pack(128);
+        transport.pack_slice(&input, &mut packed);
+        // The one-time 0xef init byte only appears once per connection, so
+        // only the first copy of `packed` carries it.
+        let two_input: Vec<u8> = packed
+            .as_ref()
+            .iter()
+            .chain(packed.as_ref()[1..].iter())
+            .copied()
+            .collect();
+
+        let mut fed = Abridged::new();
+        let mut output = BytesMut::new();
+        let n = fed.feed(&two_input, &mut output).unwrap().unwrap();
+        assert_eq!(input, output);
+
+        output.clear();
+        fed.feed(&two_input[n..], &mut output).unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn feed_resets_to_need_header_byte() {
+        let (mut transport, input, mut packed) = setup_pack(128);
+        transport.pack_slice(&input, &mut packed);
+
+        let mut fed = Abridged::new();
+        let mut output = BytesMut::new();
+        fed.feed(&packed[..2], &mut output).unwrap();
+        fed.reset();
+        output.clear();
+        let n = fed.feed(&packed, &mut output).unwrap();
+        assert_eq!(n, Some(packed.len()));
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn pack_unpack_discontiguous_chain() {
+        let (mut transport, input, mut packed) = setup_pack(128);
+        let (first, second) = input.split_at(64);
+        let mut chained = bytes::Buf::chain(first, second);
+        transport.pack(&mut chained, &mut packed);
+        assert_eq!(&packed[..2], &[0xef, 32]);
+        assert_eq!(&packed[2..], &input[..]);
+
+        let mut unpacked = BytesMut::new();
+        transport
+            .unpack(&mut &packed[1..], &mut unpacked)
+            .unwrap();
         assert_eq!(input, unpacked);
     }
 }
@@ -0,0 +1,109 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use super::Error;
+use bytes::BufMut;
+
+/// A bounds-checked cursor over a byte slice.
+///
+/// Every transport needs to pick apart a handful of header fields (a
+/// length prefix, a sequence number, a checksum...) and they all used to
+/// do it with ad-hoc slice indexing. `Decoder` gives them one audited
+/// place to do that instead: every read advances the cursor and returns
+/// [`Error::MissingBytes`] rather than panicking when the slice does not
+/// hold enough data.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+
+    /// How many bytes have been consumed so far. Once a frame has been
+    /// fully parsed, this is how much of `buf` it took up.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Reads `n` (at most 4) bytes as a little-endian unsigned integer.
+    pub fn read_uint_le(&mut self, n: usize) -> Result<u32, Error> {
+        let bytes = self.read_bytes(n)?;
+        let mut buf = [0u8; 4];
+        buf[..n].copy_from_slice(bytes);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < n {
+            return Err(Error::MissingBytes);
+        }
+        let bytes = &self.buf[self.offset..self.offset + n];
+        self.offset += n;
+        Ok(bytes)
+    }
+}
+
+/// The write-side counterpart of [`Decoder`], wrapping anything that
+/// implements [`bytes::BufMut`].
+pub struct Encoder<'a, B: BufMut> {
+    buf: &'a mut B,
+}
+
+impl<'a, B: BufMut> Encoder<'a, B> {
+    pub fn new(buf: &'a mut B) -> Self {
+        Self { buf }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.put_u8(value);
+    }
+
+    /// Writes the low `n` (at most 4) bytes of `value`, little-endian.
+    pub fn write_uint_le(&mut self, value: u32, n: usize) {
+        self.buf.put_slice(&value.to_le_bytes()[..n]);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.put_slice(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_u8_missing_bytes() {
+        let mut decoder = Decoder::new(&[]);
+        assert_eq!(decoder.read_u8(), Err(Error::MissingBytes));
+    }
+
+    #[test]
+    fn read_uint_le_tracks_offset() {
+        let mut decoder = Decoder::new(&[1, 2, 3, 4, 5]);
+        assert_eq!(decoder.read_uint_le(3).unwrap(), 0x0003_0201);
+        assert_eq!(decoder.offset(), 3);
+    }
+
+    #[test]
+    fn read_bytes_missing_bytes_does_not_panic() {
+        let mut decoder = Decoder::new(&[1, 2]);
+        assert_eq!(decoder.read_bytes(3), Err(Error::MissingBytes));
+        assert_eq!(decoder.offset(), 0);
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+mod abridged;
+mod codec;
+mod full;
+mod obfuscated;
+
+pub use abridged::Abridged;
+pub use codec::{Decoder, Encoder};
+pub use full::Full;
+pub use obfuscated::Obfuscated;
+
+use bytes::{Buf, BufMut, BytesMut};
+use grammers_crypto::RingBuffer;
+use std::fmt;
+
+/// The error type reported by the different transports when something
+/// goes wrong packing or unpacking the data they receive.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The input buffer is missing bytes to proceed. Unlike [`Error::BadLen`],
+    /// this is not terminal: the frame is well-formed so far, and re-trying
+    /// once more bytes have arrived may succeed.
+    MissingBytes,
+
+    /// A packet declared a length too small to ever hold a valid frame
+    /// (for the full transport, under the 12-byte header+crc floor).
+    /// Unlike [`Error::MissingBytes`], waiting for more bytes can never fix
+    /// this; the frame itself is malformed and the connection should be
+    /// dropped.
+    BadLen { got: usize },
+
+    /// The checksum of a packet received over the full transport did not
+    /// match the one it was sent with.
+    BadCrc { expected: u32, got: u32 },
+
+    /// A packet received over the full transport did not carry the
+    /// sequence number that was expected next.
+    BadSeq { expected: i32, got: i32 },
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MissingBytes => write!(f, "missing bytes to complete the packet"),
+            Error::BadLen { got } => write!(f, "packet declared an impossibly small length ({})", got),
+            Error::BadCrc { expected, got } => {
+                write!(f, "bad packet crc32 (expected {}, got {})", expected, got)
+            }
+            Error::BadSeq { expected, got } => write!(
+                f,
+                "bad packet sequence number (expected {}, got {})",
+                expected, got
+            ),
+        }
+    }
+}
+
+/// This trait is used to identify what can be considered a valid
+/// [transport].
+///
+/// [transport]: https://core.telegram.org/mtproto#mtproto-transport
+pub trait Transport {
+    /// Packs the input buffer payload and writes the result to the output buffer.
+    ///
+    /// `input` and `output` need not be contiguous: a [`bytes::buf::Chain`]
+    /// of several received segments can be passed as-is, without the caller
+    /// first copying it into one contiguous buffer. Transports that checksum
+    /// or encrypt the frame still copy it into a buffer of their own.
+    ///
+    /// Panics if `input.remaining()` is not divisible by 4.
+    fn pack<B: BufMut>(&mut self, input: &mut impl Buf, output: &mut B);
+
+    /// Unpacks the input buffer, and writes the result to the output buffer.
+    ///
+    /// Returns the amount of bytes consumed from the input buffer.
+    fn unpack<B: Buf>(&mut self, input: &mut B, output: &mut impl BufMut) -> Result<usize, Error>;
+
+    /// Feeds the transport with a chunk of bytes that may only hold part of
+    /// a frame, appending that frame's payload to `output`. The payload is
+    /// complete in `output` once this returns `Ok(Some(_))`; before that,
+    /// `output` may already hold part of it.
+    ///
+    /// Unlike [`Transport::unpack`], this method never re-reads bytes from
+    /// a previous call: the transport remembers how far into the current
+    /// frame it got, so it can be driven straight off a non-blocking
+    /// socket without an external reassembly buffer. Returns `Ok(Some(n))`
+    /// with the number of bytes of `chunk` that completed the frame, or
+    /// `Ok(None)` if `chunk` ran out before the frame did.
+    fn feed(&mut self, chunk: &[u8], output: &mut BytesMut) -> Result<Option<usize>, Error>;
+
+    /// Resets the state machine to its initial state.
+    fn reset(&mut self);
+
+    /// Thin wrapper over [`Transport::pack`] for callers still working with
+    /// contiguous slices, kept for backward compatibility.
+    fn pack_slice(&mut self, input: &[u8], output: &mut RingBuffer<u8>) {
+        let mut input = input;
+        self.pack(&mut input, output)
+    }
+
+    /// Thin wrapper over [`Transport::unpack`] for callers still working
+    /// with contiguous slices, kept for backward compatibility.
+    fn unpack_slice(&mut self, input: &[u8], output: &mut BytesMut) -> Result<usize, Error> {
+        let mut input = input;
+        self.unpack(&mut input, output)
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright 2020 - developers of the `grammers` project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+use super::{Error, Transport};
+use aes::cipher::{NewCipher, StreamCipher, StreamCipherSeek};
+use aes::Aes256Ctr;
+use bytes::{Buf, BufMut, BytesMut};
+use rand::Rng;
+use std::convert::TryInto;
+
+/// Wraps any other [`Transport`] to defeat deep packet inspection.
+///
+/// This is an implementation of the *obfuscated* transport used by
+/// [MTProxy] endpoints: the whole stream produced by the inner transport
+/// is additionally encrypted with AES-256-CTR, under a key derived from a
+/// randomized 64-byte header sent once at the start of the connection.
+///
+/// [MTProxy]: https://core.telegram.org/mtproto/mtproto-transports#transport-obfuscation
+pub struct Obfuscated<T: Transport> {
+    inner: T,
+    send: Option<Aes256Ctr>,
+    recv: Option<Aes256Ctr>,
+    scratch: BytesMut,
+    /// Plaintext decrypted from a previous [`Obfuscated::unpack`] call that
+    /// `inner` did not need for the frame it returned. Unlike ciphertext,
+    /// this never needs to be decrypted again, so it is kept around as-is
+    /// rather than thrown away with the rest of that call's `input`.
+    leftover: BytesMut,
+}
+
+impl<T: Transport> Obfuscated<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            send: None,
+            recv: None,
+            scratch: BytesMut::new(),
+            leftover: BytesMut::new(),
+        }
+    }
+
+    /// Generates the 64-byte init header, derives the send/recv ciphers
+    /// from it, and writes the (partially encrypted) header to `output`.
+    fn handshake<B: BufMut>(&mut self, output: &mut B) {
+        let mut header = [0u8; 64];
+        loop {
+            rand::thread_rng().fill(&mut header[..]);
+            if is_valid_header(&header) {
+                break;
+            }
+        }
+
+        let send_key_iv = &header[8..56];
+        let mut recv_key_iv = send_key_iv.to_vec();
+        recv_key_iv.reverse();
+
+        let mut send = Aes256Ctr::new_from_slices(&send_key_iv[..32], &send_key_iv[32..])
+            .expect("key and iv are always the right length");
+        let recv = Aes256Ctr::new_from_slices(&recv_key_iv[..32], &recv_key_iv[32..])
+            .expect("key and iv are always the right length");
+
+        // The last 8 bytes of the header double as a pseudo-random marker:
+        // they are replaced by themselves encrypted under the send key.
+        let mut encrypted = header;
+        send.apply_keystream(&mut encrypted);
+        header[56..64].copy_from_slice(&encrypted[56..64]);
+
+        output.put_slice(&header);
+        self.send = Some(send);
+        self.recv = Some(recv);
+    }
+}
+
+/// Bytes that a real Telegram handshake could never start with, and which
+/// other protocols use as their own magic bytes (e.g. plain-text HTTP
+/// requests); the random header must avoid generating any of them.
+fn is_valid_header(header: &[u8; 64]) -> bool {
+    let first_byte = header[0];
+    let first_dword = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let second_dword = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+    first_byte != 0xef
+        && first_dword != 0xeeeeeeee
+        && first_dword != 0xdddddddd
+        && first_dword != 0x44414548 // 'HEAD'
+        && first_dword != 0x54534f50 // 'POST'
+        && first_dword != 0x20544547 // 'GET '
+        && first_dword != 0x4954504f // 'OPTI'
+        && second_dword != 0
+}
+
+impl<T: Transport> Transport for Obfuscated<T> {
+    fn pack<B: BufMut>(&mut self, input: &mut impl Buf, output: &mut B) {
+        if self.send.is_none() {
+            self.handshake(output);
+        }
+
+        self.scratch.clear();
+        self.inner.pack(input, &mut self.scratch);
+        self.send
+            .as_mut()
+            .expect("handshake always sets up the send cipher")
+            .apply_keystream(&mut self.scratch);
+        output.put_slice(&self.scratch);
+    }
+
+    fn unpack<B: Buf>(&mut self, input: &mut B, output: &mut impl BufMut) -> Result<usize, Error> {
+        let consumed = input.remaining();
+
+        self.scratch.clear();
+        while input.has_remaining() {
+            let chunk = input.chunk();
+            self.scratch.put_slice(chunk);
+            let len = chunk.len();
+            input.advance(len);
+        }
+        self.recv
+            .as_mut()
+            .expect("Obfuscated::unpack called before the init header was sent")
+            .apply_keystream(&mut self.scratch);
+
+        // The keystream only ever advances forward, so decrypted bytes
+        // `inner` doesn't end up using this call are appended to
+        // `leftover` instead of being decrypted again (or lost) next time.
+        self.leftover.put_slice(&self.scratch);
+
+        let mut plain = &self.leftover[..];
+        let result = self.inner.unpack(&mut plain, output);
+        // Only commit the advance on success: a `MissingBytes` error may
+        // have been raised after `inner` partially read its header off
+        // `plain`, and that partial read must not be lost — the whole
+        // frame has to be re-parsed from the start once more data arrives.
+        if let Ok(n) = result {
+            debug_assert_eq!(self.leftover.len() - plain.remaining(), n);
+            self.leftover.advance(n);
+        }
+
+        result.map(|_| consumed)
+    }
+
+    fn feed(&mut self, chunk: &[u8], output: &mut BytesMut) -> Result<Option<usize>, Error> {
+        let recv = self
+            .recv
+            .as_mut()
+            .expect("Obfuscated::feed called before the init header was sent");
+        let start = recv.current_pos::<u64>();
+
+        let mut decrypted = chunk.to_vec();
+        recv.apply_keystream(&mut decrypted);
+
+        match self.inner.feed(&decrypted, output) {
+            Ok(Some(n)) => {
+                recv.seek(start + n as u64);
+                Ok(Some(n))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                recv.seek(start);
+                Err(e)
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.send = None;
+        self.recv = None;
+        self.leftover.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Full;
+
+    /// Builds two `Obfuscated` instances with ciphers already wired up to
+    /// mirror each other, the way a real client/server pair would end up
+    /// after exchanging one side's init header — without actually sending
+    /// one, so tests can drive `pack`/`unpack` directly against each other.
+    ///
+    /// Uses `Full` as the inner transport: unlike `Abridged`, its `unpack`
+    /// has no "caller already stripped the one-time init byte" convention
+    /// to additionally account for, so it isolates what these tests care
+    /// about — `Obfuscated`'s own encryption and buffering.
+    fn paired() -> (Obfuscated<Full>, Obfuscated<Full>) {
+        let mut header = [0u8; 64];
+        loop {
+            rand::thread_rng().fill(&mut header[..]);
+            if is_valid_header(&header) {
+                break;
+            }
+        }
+
+        let send_key_iv = &header[8..56];
+        let mut recv_key_iv = send_key_iv.to_vec();
+        recv_key_iv.reverse();
+
+        let mut a = Obfuscated::new(Full::new());
+        a.send = Some(Aes256Ctr::new_from_slices(&send_key_iv[..32], &send_key_iv[32..]).unwrap());
+        a.recv = Some(Aes256Ctr::new_from_slices(&recv_key_iv[..32], &recv_key_iv[32..]).unwrap());
+
+        let mut b = Obfuscated::new(Full::new());
+        b.recv = Some(Aes256Ctr::new_from_slices(&send_key_iv[..32], &send_key_iv[32..]).unwrap());
+        b.send = Some(Aes256Ctr::new_from_slices(&recv_key_iv[..32], &recv_key_iv[32..]).unwrap());
+
+        (a, b)
+    }
+
+    #[test]
+    fn pack_unpack_round_trip() {
+        let (mut a, mut b) = paired();
+        let input: Vec<u8> = (0..64).collect();
+
+        let mut packed = BytesMut::new();
+        a.pack(&mut &input[..], &mut packed);
+
+        let mut unpacked = BytesMut::new();
+        b.unpack(&mut packed, &mut unpacked).unwrap();
+        assert_eq!(input, unpacked);
+    }
+
+    #[test]
+    fn feed_across_chunks() {
+        let (mut a, mut b) = paired();
+        let input: Vec<u8> = (0..64).collect();
+
+        let mut packed = BytesMut::new();
+        a.pack(&mut &input[..], &mut packed);
+
+        let mut output = BytesMut::new();
+        for chunk in packed.chunks(7) {
+            b.feed(chunk, &mut output).unwrap();
+        }
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn is_valid_header_rejects_reserved_markers() {
+        let header = [0x41u8; 64];
+        assert!(is_valid_header(&header));
+
+        let mut first_byte_ef = header;
+        first_byte_ef[0] = 0xef;
+        assert!(!is_valid_header(&first_byte_ef));
+
+        let mut zero_second_dword = header;
+        zero_second_dword[4..8].copy_from_slice(&[0, 0, 0, 0]);
+        assert!(!is_valid_header(&zero_second_dword));
+
+        for magic in [
+            0xeeeeeeeeu32,
+            0xdddddddd,
+            0x44414548, // 'HEAD'
+            0x54534f50, // 'POST'
+            0x20544547, // 'GET '
+            0x4954504f, // 'OPTI'
+        ] {
+            let mut h = header;
+            h[0..4].copy_from_slice(&magic.to_le_bytes());
+            assert!(!is_valid_header(&h));
+        }
+    }
+}